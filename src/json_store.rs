@@ -1,28 +1,69 @@
-use super::{State, Store};
+use super::{BoxedError, State, Store};
+use serde_json::Value;
 use std::env::current_exe;
+use std::fmt;
 use std::fs;
 use std::io;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
+/// Current schema version of the persisted payload. Bump this whenever the on-disk shape of
+/// [`State`] (or the user's step structs) changes in a way old files can't be decoded into
+/// directly, and register a migration from the previous version with [`JsonStore::with_migration`].
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A migration closure upgrading a persisted state payload by one version.
+pub type Migration = Box<dyn Fn(Value) -> Result<Value, BoxedError>>;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("can't read file `{1}`")]
     ReadFile(#[source] io::Error, PathBuf),
     #[error("can't write file `{1}`")]
     WriteFile(#[source] io::Error, PathBuf),
+    #[error("can't rename `{1}` to `{2}`")]
+    RenameFile(#[source] io::Error, PathBuf, PathBuf),
     #[error("can't remove file `{1}`")]
     RemoveFile(#[source] io::Error, PathBuf),
+    #[error("state slot `{0}` is locked by another process")]
+    Locked(PathBuf),
+    #[error("can't acquire lock `{1}`")]
+    Lock(#[source] io::Error, PathBuf),
     #[error("can't decode json: {1}")]
     Decode(#[source] serde_json::Error, String),
     #[error("can't encode state into json: {1:?}")]
     Encode(#[source] serde_json::Error, String),
+    #[error("migration from version {1} failed")]
+    Migration(#[source] BoxedError, u32),
+    #[error("persisted state version mismatch: found {found}, expected {expected}")]
+    VersionMismatch { found: u32, expected: u32 },
+    #[error("insecure permissions on `{path}`: mode {mode:o}")]
+    InsecurePermissions { path: PathBuf, mode: u32 },
+    #[error("can't stat `{1}`")]
+    Stat(#[source] io::Error, PathBuf),
+    #[error("can't set permissions on `{1}`")]
+    SetPermissions(#[source] io::Error, PathBuf),
     #[error("can't find executable steam")]
     ExeStem(#[source] io::Error),
 }
 
-#[derive(Debug)]
 pub struct JsonStore {
     path: PathBuf,
+    /// Migrations keyed by the version they upgrade *from*.
+    migrations: Vec<(u32, Migration)>,
+    /// Whether to enforce owner-only permissions on the slot (see
+    /// [`JsonStore::require_secure_permissions`]).
+    require_secure_permissions: bool,
+}
+
+impl fmt::Debug for JsonStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JsonStore")
+            .field("path", &self.path)
+            .field("migrations", &self.migrations.iter().map(|(v, _)| v).collect::<Vec<_>>())
+            .field("require_secure_permissions", &self.require_secure_permissions)
+            .finish()
+    }
 }
 
 impl JsonStore {
@@ -30,39 +71,245 @@ impl JsonStore {
     pub fn new() -> Result<Self, Error> {
         let mut path = exe_stem().map_err(Error::ExeStem)?;
         path.set_extension("json");
-        Ok(Self { path })
+        Ok(Self {
+            path,
+            migrations: Vec::new(),
+            require_secure_permissions: false,
+        })
     }
 
     pub fn with_path(mut self, path: impl AsRef<Path>) -> Self {
         self.path = path.as_ref().into();
         self
     }
+
+    /// Toggles the owner-only permission enforcement (default `false`, opt-in). When enabled saves
+    /// create the slot with mode `0600` on Unix, and loads reject a file or non-sticky parent
+    /// directory that is group/world writable with [`Error::InsecurePermissions`]. It defaults off
+    /// so the mainline recovery path keeps working in ordinary group-writable locations such as
+    /// `target/debug/examples/`; turn it on for state holding secrets.
+    pub fn require_secure_permissions(mut self, require: bool) -> Self {
+        self.require_secure_permissions = require;
+        self
+    }
+
+    /// Registers a migration upgrading the persisted payload from version `from` to `from + 1`.
+    ///
+    /// On `load` an older document is run through the registered migrations in order before final
+    /// deserialization. If no migration covers a gap, `load` returns [`Error::VersionMismatch`]
+    /// instead of an opaque decode error, so the caller can react deliberately.
+    pub fn with_migration<F>(mut self, from: u32, migration: F) -> Self
+    where
+        F: Fn(Value) -> Result<Value, BoxedError> + 'static,
+    {
+        self.migrations.push((from, Box::new(migration)));
+        self
+    }
+
+    /// Rewrites the path to namespace it by `key`: `<exe_stem>.<key>.json`.
+    fn set_key(&mut self, key: &str) {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = format!("{}.{}.json", stem, key);
+        self.path.set_file_name(name);
+    }
+
+    /// Rejects a slot whose file or parent directory is group/world writable.
+    ///
+    /// A directory carrying the sticky bit (e.g. `/tmp`, mode `1777`) is exempt: the sticky bit
+    /// stops anyone but the owner from replacing our file, so a world-writable sticky directory is
+    /// still safe.
+    #[cfg(unix)]
+    fn check_permissions(&self) -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = fs::metadata(&self.path).map_err(|e| Error::Stat(e, self.path.clone()))?;
+        let file_mode = file.permissions().mode();
+        if file_mode & 0o022 != 0 {
+            return Err(Error::InsecurePermissions {
+                path: self.path.clone(),
+                mode: file_mode & 0o777,
+            });
+        }
+
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                let meta = fs::metadata(dir).map_err(|e| Error::Stat(e, dir.to_path_buf()))?;
+                let mode = meta.permissions().mode();
+                let sticky = mode & 0o1000 != 0;
+                if !sticky && mode & 0o022 != 0 {
+                    return Err(Error::InsecurePermissions {
+                        path: dir.to_path_buf(),
+                        mode: mode & 0o7777,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check_permissions(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Acquires an advisory lock on the slot, guarding against two processes writing it at once.
+    /// The returned guard releases the lock on drop.
+    fn lock(&self) -> Result<fslock::LockFile, Error> {
+        let lock_path = self.path.with_extension("lock");
+        let mut lock = fslock::LockFile::open(&lock_path)
+            .map_err(|e| Error::Lock(e, lock_path.clone()))?;
+        if !lock.try_lock().map_err(|e| Error::Lock(e, lock_path.clone()))? {
+            return Err(Error::Locked(self.path.clone()));
+        }
+        Ok(lock)
+    }
+
+    /// Upgrades a raw payload to [`SCHEMA_VERSION`] by walking the registered migrations.
+    fn migrate(&self, mut value: Value, mut version: u32) -> Result<Value, Error> {
+        if version > SCHEMA_VERSION {
+            return Err(Error::VersionMismatch {
+                found: version,
+                expected: SCHEMA_VERSION,
+            });
+        }
+        while version < SCHEMA_VERSION {
+            let migration = self
+                .migrations
+                .iter()
+                .find(|(from, _)| *from == version)
+                .map(|(_, f)| f)
+                .ok_or(Error::VersionMismatch {
+                    found: version,
+                    expected: SCHEMA_VERSION,
+                })?;
+            value = migration(value).map_err(|e| Error::Migration(e, version))?;
+            version += 1;
+        }
+        Ok(value)
+    }
+
+    /// Temporary file used to stage an atomic save. A single fixed name per slot means a temp
+    /// abandoned by a previously crashed process is removed and recreated on the next save rather
+    /// than accumulating forever; concurrent writers of the same slot are already serialized by the
+    /// advisory lock, and different slots have different base names.
+    fn tmp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+}
+
+/// Writes `bytes` to `path`, flushing and syncing the file to disk before returning. When `secure`
+/// is set the file is restricted to owner-only (`0600`) on Unix *before* the secrets are written
+/// into it.
+fn write_synced(path: &Path, bytes: &[u8], secure: bool) -> io::Result<()> {
+    let file = fs::File::create(path)?;
+    if secure {
+        set_owner_only(&file)?;
+    }
+    let mut file = file;
+    file.write_all(bytes)?;
+    file.flush()?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn set_owner_only(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn set_owner_only(_file: &fs::File) -> io::Result<()> {
+    Ok(())
 }
 
 impl Store for JsonStore {
     type Error = Error;
 
     /// Loads a step
+    ///
+    /// The payload is unwrapped from its `{ version, state }` envelope and, if it predates
+    /// [`SCHEMA_VERSION`], migrated forward before being decoded into a [`State`].
     fn load(&self) -> Result<Option<State>, Self::Error> {
+        // Refuse an untrusted-permission slot before reading its contents or opening a lock file
+        // next to it. A missing slot has nothing to protect, so we skip the check and let the read
+        // below report `None`.
+        if self.require_secure_permissions && self.path.exists() {
+            self.check_permissions()?;
+        }
+        let _lock = self.lock()?;
         let json = match fs::read_to_string(&self.path) {
             Ok(x) => x,
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
             Err(e) => return Err(Error::ReadFile(e, self.path.clone())),
         };
-        serde_json::from_str(&json).map_err(|e| Error::Decode(e, json))
+        let doc: Value = serde_json::from_str(&json).map_err(|e| Error::Decode(e, json.clone()))?;
+        // Files written before versioning are bare `State` objects without a `version` key; treat
+        // them as version 0 so registered migrations can upgrade them.
+        let (version, payload) = match doc.get("version").and_then(Value::as_u64) {
+            Some(v) => (
+                v as u32,
+                doc.get("state").cloned().unwrap_or(Value::Null),
+            ),
+            None => (0, doc),
+        };
+        let payload = self.migrate(payload, version)?;
+        serde_json::from_value(payload).map_err(|e| Error::Decode(e, json))
     }
 
     /// Saves the step
+    ///
+    /// The write is atomic: the state is serialized into a temporary file in the same directory,
+    /// flushed and `sync_all`ed, then renamed over the real path (an atomic operation on a single
+    /// filesystem). This way an interrupted save can never leave a truncated or half-written
+    /// `.json` file behind for the next `load` to choke on — exactly the crash the crate exists to
+    /// survive.
     fn save(&self, state: &State) -> Result<(), Self::Error> {
-        let json = serde_json::to_string_pretty(&state)
+        let _lock = self.lock()?;
+        let doc = serde_json::json!({
+            "version": SCHEMA_VERSION,
+            "state": state,
+        });
+        let json = serde_json::to_string_pretty(&doc)
             .map_err(|e| Error::Encode(e, format!("{:?}", state)))?;
-        fs::write(&self.path, json).map_err(|e| Error::WriteFile(e, self.path.clone()))
+
+        let tmp = self.tmp_path();
+        // Clear any stale temp file left by a previous crash so we start the atomic write from a
+        // clean slate; a missing file is fine, anything else we surface.
+        match fs::remove_file(&tmp) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::RemoveFile(e, tmp)),
+        }
+
+        if let Err(e) = write_synced(&tmp, json.as_bytes(), self.require_secure_permissions) {
+            let _ = fs::remove_file(&tmp);
+            return Err(Error::WriteFile(e, tmp));
+        }
+
+        if let Err(e) = fs::rename(&tmp, &self.path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(Error::RenameFile(e, tmp, self.path.clone()));
+        }
+
+        // Fsync the containing directory so the rename itself is durable.
+        if let Some(dir) = self.path.parent() {
+            if let Ok(dir) = fs::File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(())
     }
 
     /// Cleans the store by removing the json file
     fn clean(&self) -> Result<(), Self::Error> {
         fs::remove_file(&self.path).map_err(|e| Error::RemoveFile(e, self.path.clone()))
     }
+
+    fn set_key(&mut self, key: &str) {
+        JsonStore::set_key(self, key)
+    }
 }
 
 fn exe_stem() -> io::Result<PathBuf> {
@@ -74,3 +321,44 @@ fn exe_stem() -> io::Result<PathBuf> {
     path.set_file_name(stem);
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distinct_keys_yield_distinct_paths() {
+        let base = JsonStore {
+            path: PathBuf::from("/tmp/app.json"),
+            migrations: Vec::new(),
+            require_secure_permissions: true,
+        };
+
+        let mut a = JsonStore { ..clone_path(&base) };
+        a.set_key("job-42");
+        let mut b = JsonStore { ..clone_path(&base) };
+        b.set_key("job-7");
+
+        assert_eq!(a.path, PathBuf::from("/tmp/app.job-42.json"));
+        assert_ne!(a.path, b.path);
+    }
+
+    #[test]
+    fn dotted_key_is_preserved() {
+        let mut store = JsonStore {
+            path: PathBuf::from("/tmp/app.json"),
+            migrations: Vec::new(),
+            require_secure_permissions: true,
+        };
+        store.set_key("v1.2");
+        assert_eq!(store.path, PathBuf::from("/tmp/app.v1.2.json"));
+    }
+
+    fn clone_path(store: &JsonStore) -> JsonStore {
+        JsonStore {
+            path: store.path.clone(),
+            migrations: Vec::new(),
+            require_secure_permissions: store.require_secure_permissions,
+        }
+    }
+}
@@ -66,10 +66,16 @@
 //! Notice that, thanks to the `restore()`, our machine run from the step it was interrupted,
 //! knowing about the first coin landed on heads.
 pub use json_store::JsonStore;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{error, fmt, io};
 
 pub mod json_store;
+#[cfg(feature = "sled")]
+pub mod sled_store;
+#[cfg(feature = "sled")]
+pub use sled_store::SledStore;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -83,16 +89,204 @@ pub enum Error {
     Step(String),
 }
 
+/// Serialization format used for the per-step backup the engine keeps before each run.
+///
+/// Only JSON is offered: the codec round-trips `Box<dyn Step>`, a `typetag` trait object, and
+/// `typetag` requires a self-describing format, so compact non-self-describing formats like
+/// `bincode` can't decode the step back. The enum stays as an extension seam for future
+/// self-describing formats; select one with [`Engine::with_codec`].
+#[derive(Debug, Clone, Copy)]
+pub enum Codec {
+    Json,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Json
+    }
+}
+
+impl Codec {
+    /// Encodes a value into bytes using the selected format.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+        }
+    }
+
+    /// Decodes a value from bytes using the selected format.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
+}
+
 /// A shourtcut for the `Step::next` result
 pub type BoxedError = Box<dyn error::Error>;
 pub type StepResult = Result<Option<Box<dyn Step>>, BoxedError>;
 pub type BoxedStep = Box<dyn Step>;
 
+/// A serializable snapshot of a failed step's error.
+///
+/// Instead of collapsing the whole error chain into a flat string, we keep the top-level
+/// `message` (the outermost context frame, if one was attached), the ordered `context` frames of
+/// the source chain below it, and an optional
+/// user-defined `kind` tag. A restored program can inspect these via [`Engine::error`] and branch
+/// on what went wrong rather than just printing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepError {
+    /// Top-level error message
+    pub message: String,
+    /// Source-chain frames below `message`, outermost cause first
+    pub context: Vec<String>,
+    /// An optional user-defined tag set via [`Context::context_kind`]
+    pub kind: Option<String>,
+}
+
+impl StepError {
+    /// Flattens a boxed error and its source chain into the serializable structure.
+    fn from_boxed(e: BoxedError) -> Self {
+        let message = e.to_string();
+        let mut kind = e
+            .downcast_ref::<ContextError>()
+            .and_then(|c| c.kind.clone());
+        let mut context = Vec::new();
+        let mut current = e.source();
+        while let Some(cause) = current {
+            context.push(cause.to_string());
+            if kind.is_none() {
+                if let Some(c) = cause.downcast_ref::<ContextError>() {
+                    kind = c.kind.clone();
+                }
+            }
+            current = cause.source();
+        }
+        Self {
+            message,
+            context,
+            kind,
+        }
+    }
+}
+
+/// An error carrying a context frame, produced by the [`Context`] extension trait.
+#[derive(Debug)]
+struct ContextError {
+    context: String,
+    kind: Option<String>,
+    source: BoxedError,
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.context)
+    }
+}
+
+impl error::Error for ContextError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Attaches context frames to an error as it propagates, inspired by `anyhow`.
+///
+/// ```ignore
+/// do_thing().context("while creating cache dir")?;
+/// ```
+pub trait Context<T> {
+    /// Wraps the error with a context frame.
+    fn context<C: Into<String>>(self, context: C) -> Result<T, BoxedError>;
+    /// Wraps the error with a context frame and a user-defined `kind` tag.
+    fn context_kind<C: Into<String>, K: Into<String>>(
+        self,
+        context: C,
+        kind: K,
+    ) -> Result<T, BoxedError>;
+}
+
+impl<T, E: Into<BoxedError>> Context<T> for Result<T, E> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T, BoxedError> {
+        self.map_err(|e| {
+            Box::new(ContextError {
+                context: context.into(),
+                kind: None,
+                source: e.into(),
+            }) as BoxedError
+        })
+    }
+
+    fn context_kind<C: Into<String>, K: Into<String>>(
+        self,
+        context: C,
+        kind: K,
+    ) -> Result<T, BoxedError> {
+        self.map_err(|e| {
+            Box::new(ContextError {
+                context: context.into(),
+                kind: Some(kind.into()),
+                source: e.into(),
+            }) as BoxedError
+        })
+    }
+}
+
 /// A step of the machine should implement this trait
 #[typetag::serde]
 pub trait Step: fmt::Debug {
     /// The method is called by the engine and could optionaly return the next step
     fn run(self: Box<Self>) -> StepResult;
+
+    /// Overrides the engine's default [`RetryPolicy`] for this particular step. Return `None`
+    /// (the default) to inherit the engine's policy.
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        None
+    }
+}
+
+/// Controls how a failed [`Step::run`] is retried before the error is persisted.
+///
+/// Between attempts the engine sleeps `base_delay * 2^(attempt - 1)`, optionally spread with
+/// random jitter, and re-clones the pre-run step from its `serde_json` backup so each attempt
+/// starts from the same state.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first. `1` disables retrying.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubles on every subsequent attempt.
+    pub base_delay: Duration,
+    /// Whether to add random jitter to each delay to avoid thundering herds.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(100),
+            jitter: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay to sleep before `attempt` (1-based) retry.
+    fn delay_for(&self, attempt: u32) -> Duration {
+        let factor = 2u32.checked_pow(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        let mut delay = self.base_delay.saturating_mul(factor);
+        if self.jitter {
+            // A dependency-free jitter seeded from the wall clock, adding up to one `delay` worth
+            // of extra wait.
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            let extra = delay.mul_f64(nanos as f64 / 1_000_000_000.0);
+            delay = delay.saturating_add(extra);
+        }
+        delay
+    }
 }
 
 pub trait Store: fmt::Debug {
@@ -101,6 +295,11 @@ pub trait Store: fmt::Debug {
     fn load(&self) -> Result<Option<State>, Self::Error>;
     fn save(&self, step: &State) -> Result<(), Self::Error>;
     fn clean(&self) -> Result<(), Self::Error>;
+
+    /// Sets the instance key that namespaces this machine's persistence slot, so several machines
+    /// of the same binary don't clobber a single file. Stores that don't support keying may ignore
+    /// it; the default is a no-op.
+    fn set_key(&mut self, _key: &str) {}
 }
 
 /// Machine state with metadata to store
@@ -108,14 +307,16 @@ pub trait Store: fmt::Debug {
 pub struct State {
     /// Current state of the machine
     step: Box<dyn Step>,
-    /// An error if any
-    error: Option<String>,
+    /// A structured error if the last run failed
+    error: Option<StepError>,
 }
 
 #[derive(Debug)]
 pub struct Engine<S: Store> {
     store: S,
     state: State,
+    retry: RetryPolicy,
+    codec: Codec,
 }
 
 impl Error {
@@ -125,7 +326,7 @@ impl Error {
 }
 
 impl State {
-    fn new(step: BoxedStep, error: Option<String>) -> Self {
+    fn new(step: BoxedStep, error: Option<StepError>) -> Self {
         Self { step, error }
     }
 }
@@ -134,7 +335,32 @@ impl<S: Store> Engine<S> {
     /// Creates an Engine using initial state
     pub fn new(store: S, first_step: BoxedStep) -> Result<Self, Error> {
         let state = State::new(first_step, None);
-        Ok(Self { store, state })
+        Ok(Self {
+            store,
+            state,
+            retry: RetryPolicy::default(),
+            codec: Codec::default(),
+        })
+    }
+
+    /// Sets the default [`RetryPolicy`] applied to steps that don't override it
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Sets the [`Codec`] used for the per-step backup the engine keeps before each run
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Namespaces this machine's persistence slot by an instance key, so concurrent invocations of
+    /// the same binary (e.g. processing different inputs) each get their own slot instead of
+    /// overwriting one shared file.
+    pub fn with_key(mut self, key: impl AsRef<str>) -> Self {
+        self.store.set_key(key.as_ref());
+        self
     }
 
     /// Restores an Engine from the previous run
@@ -145,6 +371,11 @@ impl<S: Store> Engine<S> {
         Ok(self)
     }
 
+    /// Returns the structured error from the previous run, if any
+    pub fn error(&self) -> Option<&StepError> {
+        self.state.error.as_ref()
+    }
+
     /// Drops the previous error
     pub fn drop_error(mut self) -> Result<Self, Error> {
         self.state.error = None;
@@ -157,14 +388,44 @@ impl<S: Store> Engine<S> {
         if let Some(e) = self.state.error.as_ref() {
             return Err(crate::Error::Step(format!(
                 "Previous run resulted in an error: {} on step: {:?}",
-                e, self.state.step
+                error_chain(e),
+                self.state.step
             )));
         }
 
         loop {
             log::info!("Running step: {:?}", &self.state.step);
-            let step_backup = serde_json::to_string(&self.state.step)?;
-            match self.state.step.run() {
+            let step_backup = self.codec.encode(&self.state.step)?;
+            let policy = self
+                .state
+                .step
+                .retry_policy()
+                .unwrap_or_else(|| self.retry.clone());
+            let max_attempts = policy.max_attempts.max(1);
+
+            let mut attempt = 1;
+            let result = loop {
+                match self.state.step.run() {
+                    Ok(next) => break Ok(next),
+                    Err(e) if attempt < max_attempts => {
+                        let delay = policy.delay_for(attempt);
+                        log::warn!(
+                            "Step failed (attempt {}/{}), retrying in {:?}: {}",
+                            attempt,
+                            max_attempts,
+                            delay,
+                            e
+                        );
+                        std::thread::sleep(delay);
+                        // Restore the pre-run step so the retry starts from the same state.
+                        self.state.step = self.codec.decode(&step_backup)?;
+                        attempt += 1;
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+
+            match result {
                 Ok(Some(step)) => {
                     self.state.step = step;
                     self.save()?;
@@ -175,11 +436,12 @@ impl<S: Store> Engine<S> {
                     break;
                 }
                 Err(e) => {
-                    self.state.step = serde_json::from_str(&step_backup)?;
-                    let err_str = error_chain(e);
-                    self.state.error = Some(err_str.clone());
+                    self.state.step = self.codec.decode(&step_backup)?;
+                    let step_err = StepError::from_boxed(e);
+                    let rendered = error_chain(&step_err);
+                    self.state.error = Some(step_err);
                     self.save()?;
-                    return Err(Error::Step(err_str));
+                    return Err(Error::Step(rendered));
                 }
             };
         }
@@ -192,16 +454,14 @@ impl<S: Store> Engine<S> {
     }
 }
 
-/// A helper to format error with its source chain
-pub fn error_chain(e: BoxedError) -> String {
-    let mut s = e.to_string();
-    let mut current = e.as_ref().source();
-    if current.is_some() {
+/// A helper to format a [`StepError`] with its context frames
+pub fn error_chain(e: &StepError) -> String {
+    let mut s = e.message.clone();
+    if !e.context.is_empty() {
         s.push_str("\nCaused by:");
     }
-    while let Some(cause) = current {
-        s.push_str(&format!("\n\t{}", cause));
-        current = cause.source();
+    for frame in &e.context {
+        s.push_str(&format!("\n\t{}", frame));
     }
     s
 }
@@ -0,0 +1,90 @@
+//! A [`Store`] backed by the [`sled`] embedded database, available behind the `sled` feature.
+//!
+//! Unlike [`JsonStore`](crate::JsonStore), which keeps one pretty-printed file per instance key,
+//! `SledStore` persists each machine's [`State`] as a row keyed by the instance key (see
+//! [`Engine::with_key`](crate::Engine::with_key)). A single database can therefore hold the
+//! progress of many concurrently running machines, and `sled` handles concurrent access itself, so
+//! this backend suits fan-out workloads and lets you inspect progress out of band.
+//!
+//! Implementing your own backend is just a matter of implementing [`Store`] — for centralized
+//! recovery of many distributed machines you might, for example, back it with Postgres, storing
+//! one row per `(binary, key)` and serializing the `State` into a `jsonb` column.
+use super::{State, Store};
+use std::path::Path;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("can't open database `{1}`")]
+    Open(#[source] sled::Error, String),
+    #[error("can't read key `{1}`")]
+    Read(#[source] sled::Error, String),
+    #[error("can't write key `{1}`")]
+    Write(#[source] sled::Error, String),
+    #[error("can't remove key `{1}`")]
+    Remove(#[source] sled::Error, String),
+    #[error("can't flush database")]
+    Flush(#[source] sled::Error),
+    #[error("can't decode json: {1}")]
+    Decode(#[source] serde_json::Error, String),
+    #[error("can't encode state into json: {1:?}")]
+    Encode(#[source] serde_json::Error, String),
+}
+
+#[derive(Debug)]
+pub struct SledStore {
+    db: sled::Db,
+    key: String,
+}
+
+impl SledStore {
+    /// Opens (or creates) a database at `path`, using `default` as the initial instance key.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let db =
+            sled::open(path).map_err(|e| Error::Open(e, path.display().to_string()))?;
+        Ok(Self {
+            db,
+            key: "default".into(),
+        })
+    }
+}
+
+impl Store for SledStore {
+    type Error = Error;
+
+    /// Loads the state stored under the current instance key
+    fn load(&self) -> Result<Option<State>, Self::Error> {
+        let bytes = match self.db.get(&self.key) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(Error::Read(e, self.key.clone())),
+        };
+        let state = serde_json::from_slice(&bytes)
+            .map_err(|e| Error::Decode(e, String::from_utf8_lossy(&bytes).into_owned()))?;
+        Ok(Some(state))
+    }
+
+    /// Saves the state under the current instance key
+    fn save(&self, state: &State) -> Result<(), Self::Error> {
+        let json =
+            serde_json::to_vec(state).map_err(|e| Error::Encode(e, format!("{:?}", state)))?;
+        self.db
+            .insert(self.key.as_bytes(), json)
+            .map_err(|e| Error::Write(e, self.key.clone()))?;
+        self.db.flush().map_err(Error::Flush)?;
+        Ok(())
+    }
+
+    /// Removes the state stored under the current instance key
+    fn clean(&self) -> Result<(), Self::Error> {
+        self.db
+            .remove(self.key.as_bytes())
+            .map_err(|e| Error::Remove(e, self.key.clone()))?;
+        self.db.flush().map_err(Error::Flush)?;
+        Ok(())
+    }
+
+    fn set_key(&mut self, key: &str) {
+        self.key = key.to_owned();
+    }
+}